@@ -1,19 +1,57 @@
-use super::writer::Writer;
+use super::writer::{Lz4Sink, PixelSink, UncompressedSink};
 use crate::{
-    types::{color_diff, luma_diff, Channels, Op, RgbaColor, CACHE_SIZE, END_OF_IMAGE},
+    types::{
+        color_diff, luma_diff, Channels, Op, RgbaColor, CACHE_SIZE, END_OF_IMAGE,
+        OP_RUNLENGTH, OP_RUNLENGTH_END,
+    },
     util::pixel_hash,
 };
-use lz4_flex::frame::FrameEncoder;
-use std::io::{self, BufWriter, Read, Write};
+use std::io::{self, Read, Write};
+
+// The largest run that fits in a single OP_RUNLENGTH chunk. Note this is narrower than
+// the full 6-bit MASK_2 range: the top two tag values in that range (0xfe, 0xff) collide
+// with the literal Op::Rgb/Op::Rgba bytes, so only OP_RUNLENGTH..=OP_RUNLENGTH_END is safe.
+const RUN_MAX: usize = (OP_RUNLENGTH_END - OP_RUNLENGTH) as usize + 1;
+
+// How many pixels elapse between progress callbacks, so the callback overhead stays
+// negligible even on large images. Shared with the decoder so both sides report at the
+// same cadence.
+pub(crate) const PROGRESS_INTERVAL: usize = 4096;
+
+// Snapshot of how far a streaming encode/decode has progressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub pixels_in: usize,
+    pub pixels_count: usize,
+}
+
+impl Progress {
+    pub(crate) fn new(pixels_in: usize, pixels_count: usize) -> Self {
+        Self {
+            pixels_in,
+            pixels_count,
+        }
+    }
+
+    // fraction of the image processed so far, in [0.0, 1.0]
+    pub fn fraction(&self) -> f64 {
+        if self.pixels_count == 0 {
+            1.0
+        } else {
+            self.pixels_in as f64 / self.pixels_count as f64
+        }
+    }
+}
 
 // PixelEncoder is a stream encoder that encodes pixels one by one
-// - Writer is a wrapper around the underlying writer that can be either a lz4 encoder or a regular writer
+// - S is the PixelSink opcodes are written to; it can be any compression backend
 // - C is the number of channels in the image
-pub struct PixelEncoder<W: Write, const C: usize> {
-    writer: Writer<W>,
-    // runlength: u8,    // if runlength > 0 then we are in runlength encoding mode
+pub struct PixelEncoder<S: PixelSink, const C: usize> {
+    writer: S,
+    run: usize, // length of the run of identical pixels pending a flush
     pixels_in: usize, // pixels encoded so far
     pixels_count: usize,
+    finished: bool, // whether finish() has already written the end-of-image marker
 
     cache: [RgbaColor; CACHE_SIZE],
     prev_pixel: RgbaColor,
@@ -21,34 +59,37 @@ pub struct PixelEncoder<W: Write, const C: usize> {
     buffer: Vec<u8>,
 }
 
-impl<W: Write, const C: usize> PixelEncoder<W, C> {
-    pub fn new(writer: Writer<W>, pixels_count: usize) -> Self {
+impl<S: PixelSink, const C: usize> PixelEncoder<S, C> {
+    pub fn new(writer: S, pixels_count: usize) -> Self {
         Self {
             writer,
             cache: [RgbaColor([0, 0, 0, 0]); CACHE_SIZE],
-            // runlength: 0,
+            run: 0,
             pixels_in: 0,
             pixels_count,
-            prev_pixel: RgbaColor([0, 0, 0, 0]),
+            finished: false,
+            // matches the decoder's `last_px` sentinel so the very first pixel of an
+            // image that happens to equal it round-trips instead of decoding opaque
+            prev_pixel: RgbaColor([0, 0, 0, 255]),
 
             buffer: Vec::with_capacity(8),
         }
     }
+}
 
+impl<W: Write, const C: usize> PixelEncoder<Lz4Sink<W>, C> {
     pub fn new_lz4(writer: W, pixels_count: usize) -> Self {
-        Self::new(
-            Writer::Lz4Encoder(Box::new(FrameEncoder::new(writer))),
-            pixels_count,
-        )
+        Self::new(Lz4Sink::new(writer), pixels_count)
     }
+}
 
+impl<W: Write, const C: usize> PixelEncoder<UncompressedSink<W>, C> {
     pub fn new_uncompressed(writer: W, pixels_count: usize) -> Self {
-        Self::new(
-            Writer::UncompressedEncoder(BufWriter::new(writer)),
-            pixels_count,
-        )
+        Self::new(UncompressedSink::new(writer), pixels_count)
     }
+}
 
+impl<S: PixelSink, const C: usize> PixelEncoder<S, C> {
     #[inline]
     fn encode_pixel(
         &mut self,
@@ -141,39 +182,146 @@ impl<W: Write, const C: usize> PixelEncoder<W, C> {
         self.cache[hash as usize] = *curr_pixel;
     }
 
+    // writes out the pending run as one or more OP_RUNLENGTH chunks, biased by -1, each
+    // no larger than RUN_MAX. The cache is left untouched since the repeated pixel was
+    // already inserted into it when it was first encoded.
+    #[inline]
+    fn flush_run(&mut self) -> std::io::Result<()> {
+        while self.run > 0 {
+            let chunk = self.run.min(RUN_MAX);
+            self.writer
+                .write_one(u8::from(Op::RunLength) | (chunk - 1) as u8)?;
+            self.run -= chunk;
+        }
+        Ok(())
+    }
+
+    // feeds a single decoded pixel through run-length encoding, falling back to
+    // encode_pixel whenever the run is broken
+    #[inline]
+    fn write_pixel(&mut self, curr_pixel: RgbaColor) -> std::io::Result<()> {
+        self.pixels_in += 1;
+
+        if curr_pixel == self.prev_pixel {
+            self.run += 1;
+            if self.run == RUN_MAX {
+                self.flush_run()?;
+            }
+        } else {
+            self.flush_run()?;
+            self.pixels_in -= 1; // encode_pixel increments pixels_in itself
+            self.encode_pixel(curr_pixel, self.prev_pixel)?;
+        }
+
+        self.prev_pixel = curr_pixel;
+        Ok(())
+    }
+
     // flushes the remaining pixels in the cache and writes the end of image marker, automatically called after N pixels are encoded
     pub fn finish(&mut self) -> std::io::Result<()> {
-        self.writer.write_all(&END_OF_IMAGE)
+        self.flush_run()?;
+        self.writer.write_all(&END_OF_IMAGE)?;
+        self.finished = true;
+        Ok(())
     }
 
     // take a reader and encode it pixel by pixel
     pub fn encode<R: Read>(&mut self, mut reader: R) -> std::io::Result<u64> {
         io::copy(&mut reader, self)
     }
-}
 
-impl<W: Write, const C: usize> Write for PixelEncoder<W, C> {
-    // Currently always buffers C bytes before encoding a pixel, this could be improved by only buffering the remaining bytes until the next pixel boundary is reached
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let buf_len = buf.len();
-        // let mut total_bytes_written = 0;
+    // like `encode`, but invokes `on_progress` at a throttled cadence (every
+    // `PROGRESS_INTERVAL` pixels) so callers can drive a progress bar without
+    // reimplementing the pixel loop
+    pub fn encode_with_progress<R: Read, F: FnMut(Progress)>(
+        &mut self,
+        mut reader: R,
+        mut on_progress: F,
+    ) -> std::io::Result<u64> {
+        let mut total_bytes = 0u64;
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            total_bytes += read as u64;
+
+            self.write_bytes(&chunk[..read], |encoder| {
+                if encoder.pixels_in % PROGRESS_INTERVAL == 0 {
+                    on_progress(Progress::new(encoder.pixels_in, encoder.pixels_count));
+                }
+            })?;
+        }
+
+        on_progress(Progress::new(self.pixels_in, self.pixels_count));
+        Ok(total_bytes)
+    }
+
+    // shared pixel-feeding loop behind `Write::write` and `encode_with_progress`;
+    // `after_pixel` is invoked once per whole pixel encoded. Advances by whole-pixel
+    // strides rather than pushing one byte at a time: top up a pixel straddling the
+    // previous call first, then consume the rest of `buf` directly via `chunks_exact`.
+    #[inline]
+    fn write_bytes<F: FnMut(&mut Self)>(
+        &mut self,
+        mut buf: &[u8],
+        mut after_pixel: F,
+    ) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            let needed = C - self.buffer.len();
+            let take = needed.min(buf.len());
+            self.buffer.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
 
-        for byte in buf {
-            self.buffer.push(*byte);
             if self.buffer.len() == C {
                 let mut curr_pixel = RgbaColor([0, 0, 0, 255]);
                 curr_pixel.0[..C].copy_from_slice(&self.buffer);
-                self.encode_pixel(curr_pixel, self.prev_pixel)?;
-                self.prev_pixel = curr_pixel;
+                self.write_pixel(curr_pixel)?;
                 self.buffer.clear();
-                // total_bytes_written += C;
+                after_pixel(self);
             }
         }
 
+        let mut chunks = buf.chunks_exact(C);
+        for chunk in &mut chunks {
+            let mut curr_pixel = RgbaColor([0, 0, 0, 255]);
+            curr_pixel.0[..C].copy_from_slice(chunk);
+            self.write_pixel(curr_pixel)?;
+            after_pixel(self);
+        }
+        self.buffer.extend_from_slice(chunks.remainder());
+
         if self.pixels_in == self.pixels_count {
             self.finish()?;
         }
 
+        Ok(())
+    }
+}
+
+// Guards against a dropped encoder leaving a truncated stream: if `finish()` was never
+// called (e.g. an early return via `?` before `pixels_count` was reached), write the
+// end-of-image marker and flush the underlying writer so the stream is terminated
+// rather than cut off. The stream then holds only the pixels encoded so far, and bytes
+// of an incomplete pixel left in the buffer are discarded. Errors can't be surfaced from
+// `Drop`, so callers should still prefer calling `finish()` explicitly.
+impl<S: PixelSink, const C: usize> Drop for PixelEncoder<S, C> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let _ = self.finish();
+        let _ = self.writer.flush();
+    }
+}
+
+impl<S: PixelSink, const C: usize> Write for PixelEncoder<S, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let buf_len = buf.len();
+        self.write_bytes(buf, |_| {})?;
         Ok(buf_len)
     }
 
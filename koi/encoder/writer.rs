@@ -0,0 +1,57 @@
+use lz4_flex::frame::FrameEncoder;
+use std::io::{BufWriter, Write};
+
+// PixelSink is the byte sink a PixelEncoder writes encoded opcodes to. Implement this
+// trait to plug in a custom compression backend (e.g. a zstd frame encoder) without
+// touching the core pixel encoding logic in `stream.rs`.
+pub trait PixelSink {
+    fn write_one(&mut self, byte: u8) -> std::io::Result<()>;
+    fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+// Built-in backend that wraps the underlying writer in an LZ4 frame.
+pub struct Lz4Sink<W: Write>(Box<FrameEncoder<W>>);
+
+impl<W: Write> Lz4Sink<W> {
+    pub fn new(writer: W) -> Self {
+        Self(Box::new(FrameEncoder::new(writer)))
+    }
+}
+
+impl<W: Write> PixelSink for Lz4Sink<W> {
+    fn write_one(&mut self, byte: u8) -> std::io::Result<()> {
+        self.0.write_all(&[byte])
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Built-in backend that writes opcodes straight through, buffered.
+pub struct UncompressedSink<W: Write>(BufWriter<W>);
+
+impl<W: Write> UncompressedSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self(BufWriter::new(writer))
+    }
+}
+
+impl<W: Write> PixelSink for UncompressedSink<W> {
+    fn write_one(&mut self, byte: u8) -> std::io::Result<()> {
+        self.0.write_all(&[byte])
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
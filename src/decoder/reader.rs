@@ -0,0 +1,43 @@
+use lz4_flex::frame::FrameDecoder;
+use std::io::Read;
+
+// PixelSource is the byte source a PixelDecoder reads encoded opcodes from. Implement
+// this trait to plug in a custom decompression backend (e.g. a zstd frame decoder)
+// without touching the core pixel decoding logic in `stream.rs`.
+pub trait PixelSource {
+    fn read<const N: usize>(&mut self) -> std::io::Result<[u8; N]>;
+}
+
+// Built-in backend that reads opcodes out of an LZ4 frame.
+pub struct Lz4Source<R: Read>(FrameDecoder<R>);
+
+impl<R: Read> Lz4Source<R> {
+    pub fn new(reader: R) -> Self {
+        Self(FrameDecoder::new(reader))
+    }
+}
+
+impl<R: Read> PixelSource for Lz4Source<R> {
+    fn read<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+// Built-in backend that reads opcodes straight from the underlying reader.
+pub struct UncompressedSource<R: Read>(R);
+
+impl<R: Read> UncompressedSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self(reader)
+    }
+}
+
+impl<R: Read> PixelSource for UncompressedSource<R> {
+    fn read<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
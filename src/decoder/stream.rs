@@ -1,40 +1,83 @@
-use lz4_flex::frame::FrameDecoder;
 use std::io::Read;
 
-use super::reader::Reader;
-use crate::{types::*, util::pixel_hash};
+use super::reader::{Lz4Source, PixelSource, UncompressedSource};
+use crate::{
+    encoder::stream::{Progress, PROGRESS_INTERVAL},
+    types::*,
+    util::pixel_hash,
+};
 
-pub struct PixelDecoder<R: Read, const C: usize> {
-    read_decoder: Reader<R>,
+pub struct PixelDecoder<S: PixelSource, const C: usize> {
+    read_decoder: S,
     cache: [RgbaColor; 64],
     last_px: RgbaColor,
     pixels_in: usize,    // pixels decoded so far
     pixels_count: usize, // total number of pixels in the image
+    pending_run: usize,  // remaining pixels owed from an in-progress OP_RUNLENGTH
 }
 
-impl<R: Read, const C: usize> PixelDecoder<R, C> {
-    pub fn new(data: Reader<R>, pixels_count: usize) -> Self {
+impl<S: PixelSource, const C: usize> PixelDecoder<S, C> {
+    pub fn new(data: S, pixels_count: usize) -> Self {
         Self {
             read_decoder: data,
             cache: [RgbaColor([0, 0, 0, 0]); 64],
             last_px: RgbaColor([0, 0, 0, 255]),
             pixels_in: 0,
             pixels_count,
+            pending_run: 0,
         }
     }
+}
 
+impl<R: Read, const C: usize> PixelDecoder<Lz4Source<R>, C> {
     pub fn new_lz4(data: R, pixels_count: usize) -> Self {
-        Self::new(Reader::Lz4Decoder(FrameDecoder::new(data)), pixels_count)
+        Self::new(Lz4Source::new(data), pixels_count)
     }
+}
 
+impl<R: Read, const C: usize> PixelDecoder<UncompressedSource<R>, C> {
     pub fn new_uncompressed(data: R, pixels_count: usize) -> Self {
-        Self::new(Reader::UncompressedDecoder(data), pixels_count)
+        Self::new(UncompressedSource::new(data), pixels_count)
+    }
+}
+
+impl<S: PixelSource, const C: usize> PixelDecoder<S, C> {
+    // decodes every remaining pixel into `buf`, invoking `on_progress` at a throttled
+    // cadence (every `PROGRESS_INTERVAL` pixels) so callers can drive a progress bar
+    pub fn read_to_end_with_progress<F: FnMut(Progress)>(
+        &mut self,
+        buf: &mut Vec<u8>,
+        mut on_progress: F,
+    ) -> std::io::Result<usize> {
+        let start_len = buf.len();
+        let mut pixel = [0u8; 4];
+
+        // stops once `pixels_count` pixels are decoded; the end-of-image marker that
+        // follows is not read
+        while self.pixels_in < self.pixels_count {
+            self.read(&mut pixel)?;
+            buf.extend_from_slice(&pixel[..C]);
+
+            if self.pixels_in % PROGRESS_INTERVAL == 0 {
+                on_progress(Progress::new(self.pixels_in, self.pixels_count));
+            }
+        }
+
+        on_progress(Progress::new(self.pixels_in, self.pixels_count));
+        Ok(buf.len() - start_len)
     }
 }
 
 // implement read trait for Decoder
-impl<R: Read, const C: usize> Read for PixelDecoder<R, C> {
+impl<S: PixelSource, const C: usize> Read for PixelDecoder<S, C> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending_run > 0 {
+            self.pending_run -= 1;
+            buf[..4].copy_from_slice(&self.last_px.0);
+            self.pixels_in += 1;
+            return Ok(1);
+        }
+
         let mut n = 1;
         let [b1] = self.read_decoder.read::<1>()?;
         let mut pixel = RgbaColor([0, 0, 0, 255]);
@@ -55,7 +98,7 @@ impl<R: Read, const C: usize> Read for PixelDecoder<R, C> {
             OP_INDEX..=OP_INDEX_END => {
                 buf[..C].copy_from_slice(&self.cache[b1 as usize].0[..C]);
                 self.last_px = self.cache[b1 as usize];
-                self.pixels_in += n;
+                self.pixels_in += 1;
                 return Ok(n);
             }
             OP_RGB => {
@@ -67,7 +110,11 @@ impl<R: Read, const C: usize> Read for PixelDecoder<R, C> {
                 n += 4;
             }
             OP_RUNLENGTH..=OP_RUNLENGTH_END => {
-                // let run = (b1 & MASK_2) as usize + 1;
+                let run = (b1 & MASK_2) as usize + 1;
+                self.pending_run = run - 1;
+                buf[..4].copy_from_slice(&self.last_px.0);
+                self.pixels_in += 1;
+                return Ok(n);
             }
             OP_DIFF..=OP_DIFF_END => {
                 pixel = self.last_px.apply_diff(b1);
@@ -83,8 +130,248 @@ impl<R: Read, const C: usize> Read for PixelDecoder<R, C> {
         buf[..4].copy_from_slice(&pixel.0);
         self.cache[pixel_hash(pixel) as usize] = pixel;
         self.last_px = pixel;
-        self.pixels_in += n;
+        self.pixels_in += 1;
 
         Ok(n)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::stream::PixelEncoder;
+    use std::io::Write;
+
+    fn round_trip(pixels: &[[u8; 4]]) {
+        let mut encoded = Vec::new();
+        let mut encoder: PixelEncoder<_, 4> =
+            PixelEncoder::new_uncompressed(&mut encoded, pixels.len());
+        for pixel in pixels {
+            encoder.write_all(pixel).unwrap();
+        }
+        encoder.finish().unwrap();
+        encoder.flush().unwrap();
+        drop(encoder);
+
+        let mut decoder: PixelDecoder<_, 4> =
+            PixelDecoder::new_uncompressed(encoded.as_slice(), pixels.len());
+        for expected in pixels {
+            let mut buf = [0u8; 4];
+            decoder.read(&mut buf).unwrap();
+            assert_eq!(&buf, expected);
+        }
+    }
+
+    #[test]
+    fn round_trips_solid_color() {
+        let pixels = vec![[10, 20, 30, 255]; 200];
+        round_trip(&pixels);
+    }
+
+    #[test]
+    fn round_trips_runs_spanning_multiple_chunks() {
+        // a run of 124 pixels must be split into two OP_RUNLENGTH chunks; if the chunk
+        // size reused the full 6-bit MASK_2 range (64) instead of the narrower op range
+        // (62), the emitted tag byte would collide with Op::Rgb/Op::Rgba.
+        let pixels = vec![[42, 99, 7, 255]; 124];
+        round_trip(&pixels);
+    }
+
+    #[test]
+    fn round_trips_leading_transparent_black_pixel() {
+        // (0,0,0,0) matches the cache's all-zero initial entries; an encoder whose initial
+        // previous pixel were also all-zero would fold it into a run that the decoder,
+        // starting from opaque black, would expand into the wrong color.
+        let mut pixels = vec![[0, 0, 0, 0]];
+        pixels.extend(vec![[0, 0, 0, 0]; 5]);
+        pixels.push([10, 20, 30, 255]);
+        round_trip(&pixels);
+    }
+
+    #[test]
+    fn round_trips_gradient() {
+        let pixels: Vec<[u8; 4]> = (0..256u16)
+            .map(|i| [i as u8, (i * 2) as u8, (i * 3) as u8, 255])
+            .collect();
+        round_trip(&pixels);
+    }
+
+    #[test]
+    fn round_trips_writes_split_at_non_pixel_boundaries() {
+        let pixels: Vec<[u8; 4]> = (0..50u16)
+            .map(|i| [i as u8, (i * 3) as u8, (i * 7) as u8, 255])
+            .collect();
+        let bytes: Vec<u8> = pixels.iter().flatten().copied().collect();
+
+        let mut encoded = Vec::new();
+        let mut encoder: PixelEncoder<_, 4> =
+            PixelEncoder::new_uncompressed(&mut encoded, pixels.len());
+        // 7 does not divide evenly into 4-byte pixels, so every write straddles a
+        // pixel boundary differently
+        for chunk in bytes.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap();
+        encoder.flush().unwrap();
+        drop(encoder);
+
+        let mut decoder: PixelDecoder<_, 4> =
+            PixelDecoder::new_uncompressed(encoded.as_slice(), pixels.len());
+        for expected in &pixels {
+            let mut buf = [0u8; 4];
+            decoder.read(&mut buf).unwrap();
+            assert_eq!(&buf, expected);
+        }
+    }
+
+    #[test]
+    fn drop_before_finish_still_writes_end_of_image() {
+        let pixels: Vec<[u8; 4]> = vec![[1, 2, 3, 255], [4, 5, 6, 255], [7, 8, 9, 255]];
+        let mut encoded = Vec::new();
+        {
+            // only 3 of 10 expected pixels are written, and finish() is never called
+            let mut encoder: PixelEncoder<_, 4> =
+                PixelEncoder::new_uncompressed(&mut encoded, 10);
+            for pixel in &pixels {
+                encoder.write_all(pixel).unwrap();
+            }
+            // encoder dropped here
+        }
+
+        assert!(encoded.ends_with(&END_OF_IMAGE));
+
+        let mut decoder: PixelDecoder<_, 4> =
+            PixelDecoder::new_uncompressed(encoded.as_slice(), pixels.len());
+        for expected in &pixels {
+            let mut buf = [0u8; 4];
+            decoder.read(&mut buf).unwrap();
+            assert_eq!(&buf, expected);
+        }
+    }
+
+    #[test]
+    fn encode_with_progress_reports_throttled_progress() {
+        let pixels: Vec<[u8; 4]> = (0..(PROGRESS_INTERVAL as u32 * 2))
+            .map(|i| [i as u8, (i * 2) as u8, (i * 3) as u8, 255])
+            .collect();
+        let bytes: Vec<u8> = pixels.iter().flatten().copied().collect();
+
+        let mut encoded = Vec::new();
+        let mut encoder: PixelEncoder<_, 4> =
+            PixelEncoder::new_uncompressed(&mut encoded, pixels.len());
+
+        let mut reports = Vec::new();
+        encoder
+            .encode_with_progress(bytes.as_slice(), |p| reports.push(p))
+            .unwrap();
+
+        assert!(!reports.is_empty());
+        for window in reports.windows(2) {
+            assert!(window[1].pixels_in >= window[0].pixels_in);
+        }
+        let last = *reports.last().unwrap();
+        assert_eq!(last.pixels_in, pixels.len());
+        assert_eq!(last.pixels_count, pixels.len());
+        assert!((last.fraction() - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn read_to_end_with_progress_reports_decoded_count() {
+        let pixels: Vec<[u8; 4]> = (0..(PROGRESS_INTERVAL as u32 * 2))
+            .map(|i| [i as u8, (i * 2) as u8, (i * 3) as u8, 255])
+            .collect();
+
+        let mut encoded = Vec::new();
+        let mut encoder: PixelEncoder<_, 4> =
+            PixelEncoder::new_uncompressed(&mut encoded, pixels.len());
+        for pixel in &pixels {
+            encoder.write_all(pixel).unwrap();
+        }
+        encoder.finish().unwrap();
+        encoder.flush().unwrap();
+        drop(encoder);
+
+        let mut decoder: PixelDecoder<_, 4> =
+            PixelDecoder::new_uncompressed(encoded.as_slice(), pixels.len());
+
+        let mut reports = Vec::new();
+        let mut out = Vec::new();
+        decoder
+            .read_to_end_with_progress(&mut out, |p| reports.push(p))
+            .unwrap();
+
+        assert_eq!(out.len(), pixels.len() * 4);
+        assert!(!reports.is_empty());
+        let last = *reports.last().unwrap();
+        assert_eq!(last.pixels_in, pixels.len());
+        assert_eq!(last.pixels_count, pixels.len());
+    }
+
+    #[test]
+    fn round_trips_through_a_custom_pixel_sink_and_source() {
+        use crate::encoder::writer::PixelSink;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // a trivial backend that isn't one of the built-in Lz4/Uncompressed ones,
+        // proving the PixelSink/PixelSource traits are the only thing PixelEncoder and
+        // PixelDecoder actually require
+        struct SharedVecSink(Rc<RefCell<Vec<u8>>>);
+
+        impl PixelSink for SharedVecSink {
+            fn write_one(&mut self, byte: u8) -> std::io::Result<()> {
+                self.0.borrow_mut().push(byte);
+                Ok(())
+            }
+
+            fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+                self.0.borrow_mut().extend_from_slice(bytes);
+                Ok(())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        struct SliceSource<'a> {
+            data: &'a [u8],
+            pos: usize,
+        }
+
+        impl<'a> PixelSource for SliceSource<'a> {
+            fn read<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+                let mut buf = [0u8; N];
+                buf.copy_from_slice(&self.data[self.pos..self.pos + N]);
+                self.pos += N;
+                Ok(buf)
+            }
+        }
+
+        let pixels: Vec<[u8; 4]> = vec![[1, 2, 3, 255], [1, 2, 3, 255], [9, 9, 9, 255]];
+        let storage = Rc::new(RefCell::new(Vec::new()));
+
+        let mut encoder: PixelEncoder<SharedVecSink, 4> =
+            PixelEncoder::new(SharedVecSink(storage.clone()), pixels.len());
+        for pixel in &pixels {
+            encoder.write_all(pixel).unwrap();
+        }
+        encoder.finish().unwrap();
+        encoder.flush().unwrap();
+
+        let encoded = storage.borrow().clone();
+        let mut decoder = PixelDecoder::<SliceSource, 4>::new(
+            SliceSource {
+                data: &encoded,
+                pos: 0,
+            },
+            pixels.len(),
+        );
+
+        for expected in &pixels {
+            let mut buf = [0u8; 4];
+            decoder.read(&mut buf).unwrap();
+            assert_eq!(&buf, expected);
+        }
+    }
+}